@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::mem;
@@ -6,21 +7,110 @@ use std::str;
 fn main() {
     use std::process::exit;
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("usage: {} <image>", args[0]);
-        exit(1);
-    }
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let path = args.iter().skip(1).find(|arg| *arg != "--strict");
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            println!("usage: {} [--strict] <image>", args[0]);
+            exit(1);
+        }
+    };
 
-    let file = fs::read(&args[1]).unwrap();
-    let image = read_png(file).unwrap();
+    let file = fs::read(path).unwrap();
+    let options = DecodeOptions { strict };
+    let limits = Limits::default();
+    let image = read_png(file, &options, &limits).unwrap();
 
     println!("{:?}", image);
 }
 
+/// knobs that affect how permissive `read_png` is about malformed-but-parseable input
+#[derive(Debug, Clone, Copy, Default)]
+struct DecodeOptions {
+    /// reject files whose chunk CRC-32 or IDAT Adler-32 checksum doesn't match the stored value,
+    /// rather than silently decoding them anyway
+    strict: bool,
+}
+
+/// bounds on the pixel buffer `read_png` is willing to allocate, so a tiny file with a huge
+/// declared width/height can't be used to exhaust memory
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// maximum width * height the IHDR is allowed to declare
+    max_pixels: u64,
+    /// optional cap on the final pixel buffer size in bytes (after accounting for channels)
+    max_bytes: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // 2^26 pixels is ~8K x 8K, comfortably above any image we expect to see in practice
+        Limits { max_pixels: 1 << 26, max_bytes: None }
+    }
+}
+
+/// compute width * height * channels as a byte count, rejecting zero dimensions, overflow, or a
+/// size outside `limits`
+fn checked_image_size(width: u32, height: u32, channels: usize, limits: &Limits) -> Option<usize> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let pixels = (width as u64).checked_mul(height as u64)?;
+    if pixels > limits.max_pixels {
+        return None;
+    }
+
+    let bytes = pixels.checked_mul(channels as u64)?;
+    if let Some(max_bytes) = limits.max_bytes {
+        if bytes > max_bytes {
+            return None;
+        }
+    }
+
+    usize::try_from(bytes).ok()
+}
+
+/// total bytes of filtered scanline data (one filter-type byte plus `width * bpp` pixel bytes per
+/// row) that `width`x`height` at `bpp` decompresses to, accounting for Adam7's extra per-pass
+/// filter bytes when interlaced; used to bound how much `decompress_image` is willing to inflate
+/// so a small IDAT stream can't "zip bomb" its way to an unbounded allocation
+fn max_filtered_bytes(width: u32, height: u32, bpp: usize, interlace_method: u8) -> Option<usize> {
+    let total = if interlace_method == 0 {
+        let stride = (width as u64).checked_mul(bpp as u64)?;
+        stride.checked_add(1)?.checked_mul(height as u64)?
+    } else {
+        let mut total = 0u64;
+        for &(x_start, y_start, x_step, y_step) in &ADAM7_PASSES {
+            let (pass_width, pass_height) = adam7_pass_dimensions(width, height, x_start, y_start, x_step, y_step);
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+            let stride = (pass_width as u64).checked_mul(bpp as u64)?;
+            let pass_bytes = stride.checked_add(1)?.checked_mul(pass_height as u64)?;
+            total = total.checked_add(pass_bytes)?;
+        }
+        total
+    };
+
+    usize::try_from(total).ok()
+}
+
 #[derive(Debug)]
 struct PNG {
     width: u32,
     height: u32,
+    // the on-disk color type; indexed and grayscale images keep this even after a tRNS chunk
+    // adds an alpha channel, so callers can tell them apart from truecolor, truecolor+alpha or
+    // grayscale+alpha output with the same `channels` count
+    color_type: ColorType,
+    // number of bytes per pixel actually present in `pixels`; indexed images are expanded to RGB
+    // (or RGBA, if a tRNS chunk applies) when depalettized, so this can differ from
+    // `bytes_per_pixel(color_type)`
+    channels: u8,
+    pixels: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -34,25 +124,64 @@ enum PNGChunk<'a> {
         filter_method: u8,
         interlace_method: u8,
     },
-    PLTE,
+    PLTE(&'a [u8]),
+    TRNS(&'a [u8]),
     IDAT(&'a [u8]),
     IEND,
     Ancillary,
 }
 
-fn read_png(file: Vec<u8>) -> Option<PNG> {
+/// the five color types PNG defines (spec section 11.2.2), named for the pixel layout they
+/// produce rather than their raw IHDR byte value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorType {
+    Grayscale,
+    Truecolor,
+    Indexed,
+    GrayscaleAlpha,
+    TruecolorAlpha,
+}
+
+impl ColorType {
+    fn from_raw(color_type: u8) -> Option<ColorType> {
+        match color_type {
+            0 => Some(ColorType::Grayscale),
+            2 => Some(ColorType::Truecolor),
+            3 => Some(ColorType::Indexed),
+            4 => Some(ColorType::GrayscaleAlpha),
+            6 => Some(ColorType::TruecolorAlpha),
+            _ => None,
+        }
+    }
+}
+
+/// bytes per pixel as stored in the IDAT scanlines, assuming an 8 bit sample depth; this is the
+/// on-disk layout and doesn't account for alpha a tRNS chunk may add on top of it
+fn bytes_per_pixel(color_type: ColorType) -> usize {
+    match color_type {
+        ColorType::Grayscale => 1,
+        ColorType::Truecolor => 3,
+        ColorType::Indexed => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::TruecolorAlpha => 4,
+    }
+}
+
+fn read_png(file: Vec<u8>, options: &DecodeOptions, limits: &Limits) -> Option<PNG> {
     let png_signature = [137, 80, 78, 71, 13, 10, 26, 10];
     for i in 0..8 {
         if file[i] != png_signature[i] {
             return None;
         }
     }
-    let chunks = read_chunks(&file[8..])?;
+    let chunks = read_chunks(&file[8..], options)?;
 
     // extract the width and height from the header chunk, also check if we support the image
     // format that we recieved
     let image_width;
     let image_height;
+    let image_color_type;
+    let image_interlace_method;
     match chunks[0] {
         PNGChunk::IHDR {
             width,
@@ -65,17 +194,15 @@ fn read_png(file: Vec<u8>) -> Option<PNG> {
         } => {
             image_width = width;
             image_height = height;
+            image_interlace_method = interlace_method;
 
             // we'll only support 8 bit samples
             if bit_depth != 8 {
                 return None;
             }
 
-            // TODO: for now we'll only support truecolor images, in the future we'll want to 
-            // support truecolor with alpha (aka color type 6)
-            if color_type != 2 {
-                return None
-            }
+            // grayscale, truecolor, palette, grayscale+alpha and truecolor+alpha are all supported
+            image_color_type = ColorType::from_raw(color_type)?;
 
             // only DEFLATE (RFC-1950, RFC-1951) compression is defined by the PNG standard
             if compression_method != 0 {
@@ -87,9 +214,8 @@ fn read_png(file: Vec<u8>) -> Option<PNG> {
                 return None;
             }
 
-            // TODO: for now we'll only support images with no interlacing, we need to find out if
-            // there's any use in supporting Adam7 interlacing
-            if interlace_method != 0 {
+            // 0 is no interlacing, 1 is Adam7; anything else is a spec violation
+            if interlace_method > 1 {
                 return None
             }
         },
@@ -101,22 +227,108 @@ fn read_png(file: Vec<u8>) -> Option<PNG> {
 
     // get the image data and see if there are errors in the rest of the chunks
     let mut image_data = Vec::<u8>::new();
+    let mut palette: Option<&[u8]> = None;
+    let mut trns: Option<&[u8]> = None;
     for chunk in &chunks[1..] {
         match chunk {
             // more than one ihdr chunk is an error
             PNGChunk::IHDR {..} => return None,
             PNGChunk::IDAT(chunk_data) => image_data.extend_from_slice(chunk_data),
-            // we don't support palletized images
-            PNGChunk::PLTE => return None,
+            // the first PLTE chunk is the one that applies; a second one is a spec violation but
+            // we don't bother rejecting it since we never read past the first
+            PNGChunk::PLTE(chunk_data) => {
+                if chunk_data.len() % 3 != 0 {
+                    return None;
+                }
+                if palette.is_none() {
+                    palette = Some(chunk_data);
+                }
+            }
+            // likewise, only the first tRNS chunk applies
+            PNGChunk::TRNS(chunk_data) if trns.is_none() => {
+                trns = Some(chunk_data);
+            }
             _ => ()
         }
     }
-    decompress_image(&image_data)?;
 
-    Some(PNG { width: image_width, height: image_height })
+    // palette-indexed images can't be decoded without the palette that maps indices to color
+    if image_color_type == ColorType::Indexed && palette.is_none() {
+        return None;
+    }
+
+    // tRNS only carries transparency for grayscale and indexed images (PNG spec section 11.3.2);
+    // a grayscale sample is marked transparent by a single 2-byte big-endian gray value, of which
+    // only the low byte matters at our 8 bit sample depth
+    let grayscale_trns_value = match (image_color_type, trns) {
+        (ColorType::Grayscale, Some(trns)) => Some(*trns.get(1)?),
+        _ => None,
+    };
+
+    // reject the file before we spend any effort decoding it if the declared dimensions would
+    // overflow or exceed the configured limits; account for the alpha channel tRNS adds on top of
+    // the on-disk layout
+    let output_channels = match image_color_type {
+        ColorType::Indexed => if trns.is_some() { 4 } else { 3 },
+        ColorType::Grayscale if grayscale_trns_value.is_some() => 2,
+        _ => bytes_per_pixel(image_color_type),
+    };
+    checked_image_size(image_width, image_height, output_channels, limits)?;
+
+    let bpp = bytes_per_pixel(image_color_type);
+
+    // bound the decompressed IDAT output by what the declared (already limit-checked) dimensions
+    // actually require, so a tiny file with a zip-bomb IDAT stream can't inflate unbounded
+    let max_filtered_bytes = max_filtered_bytes(image_width, image_height, bpp, image_interlace_method)?;
+    let filtered_scanlines = decompress_image(&image_data, options, max_filtered_bytes)?;
+    let pixels = match image_interlace_method {
+        0 => unfilter(&filtered_scanlines, image_width, image_height, bpp)?,
+        _ => unfilter_adam7(&filtered_scanlines, image_width, image_height, bpp)?,
+    };
+
+    let (channels, pixels) = match image_color_type {
+        ColorType::Indexed => {
+            let pixels = depalettize(&pixels, palette?, trns)?;
+            (output_channels as u8, pixels)
+        }
+        ColorType::Grayscale => match grayscale_trns_value {
+            Some(trns_value) => (2, apply_grayscale_trns(&pixels, trns_value)),
+            None => (bpp as u8, pixels),
+        },
+        _ => (bpp as u8, pixels),
+    };
+
+    Some(PNG { width: image_width, height: image_height, color_type: image_color_type, channels, pixels })
 }
 
-fn read_chunks(mut file: &[u8]) -> Option<Vec<PNGChunk>> {
+/// expand palette indices into their color values using the PLTE chunk's entries, each of which
+/// is 3 bytes (PNG spec section 11.2.3); if `trns` is present each entry's alpha is looked up by
+/// index too, defaulting to fully opaque for indices past the end of the tRNS chunk
+fn depalettize(indices: &[u8], palette: &[u8], trns: Option<&[u8]>) -> Option<Vec<u8>> {
+    let out_bpp = if trns.is_some() { 4 } else { 3 };
+    let mut pixels = Vec::with_capacity(indices.len() * out_bpp);
+    for &index in indices {
+        let start = index as usize * 3;
+        pixels.extend_from_slice(palette.get(start..start + 3)?);
+        if let Some(trns) = trns {
+            pixels.push(trns.get(index as usize).copied().unwrap_or(255));
+        }
+    }
+    Some(pixels)
+}
+
+/// add an alpha channel to grayscale samples, using the tRNS-specified gray value to mark fully
+/// transparent pixels and treating everything else as fully opaque (PNG spec section 11.3.2)
+fn apply_grayscale_trns(samples: &[u8], trns_value: u8) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(samples.len() * 2);
+    for &sample in samples {
+        pixels.push(sample);
+        pixels.push(if sample == trns_value { 0 } else { 255 });
+    }
+    pixels
+}
+
+fn read_chunks<'a>(mut file: &'a [u8], options: &DecodeOptions) -> Option<Vec<PNGChunk<'a>>> {
     let mut chunks = Vec::new();
 
     while file.len() != 0 {
@@ -128,7 +340,8 @@ fn read_chunks(mut file: &[u8]) -> Option<Vec<PNGChunk>> {
             Err(_) => return None,
         };
 
-        let mut chunk_data = read_bytes(&mut file, length as usize)?;
+        let full_chunk_data = read_bytes(&mut file, length as usize)?;
+        let mut chunk_data = full_chunk_data;
         chunks.push(match chunk_type_str {
             "IHDR" => {
                 let width = read_u32(&mut chunk_data)?;
@@ -149,7 +362,8 @@ fn read_chunks(mut file: &[u8]) -> Option<Vec<PNGChunk>> {
                 }
             }
             "IDAT" => PNGChunk::IDAT(chunk_data),
-            "PLTE" => PNGChunk::PLTE,
+            "PLTE" => PNGChunk::PLTE(chunk_data),
+            "tRNS" => PNGChunk::TRNS(chunk_data),
             "IEND" => PNGChunk::IEND,
             _ => match chunk_type[0] & (1 << 5) == (1 << 5) {
                 true => PNGChunk::Ancillary,
@@ -157,15 +371,37 @@ fn read_chunks(mut file: &[u8]) -> Option<Vec<PNGChunk>> {
             },
         });
 
-        // all chunks end with a 4 byte CRC at the end, we aren't doing error checking so there's
-        // nothing of interest to do with this value
-        read_u32(&mut file);
+        // all chunks end with a CRC-32 computed over the chunk type and data; in strict mode a
+        // mismatch means the file is corrupt
+        let stored_crc = read_u32(&mut file)?;
+        if options.strict {
+            let mut crc_input = Vec::with_capacity(4 + full_chunk_data.len());
+            crc_input.extend_from_slice(chunk_type);
+            crc_input.extend_from_slice(full_chunk_data);
+            if crc32(&crc_input) != stored_crc {
+                return None;
+            }
+        }
     }
 
     Some(chunks)
 }
 
-fn decompress_image(mut image_data: &[u8]) ->Option<()> {
+/// compute the CRC-32 used by PNG chunk trailers (ISO 3309 / ITU-T V.42, polynomial 0xEDB88320,
+/// reflected, initialized to all-ones, inverted on output)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+fn decompress_image(mut image_data: &[u8], options: &DecodeOptions, max_output_bytes: usize) -> Option<Vec<u8>> {
     let cmf = read_u8(&mut image_data)?;
     let flg = read_u8(&mut image_data)?;
 
@@ -183,13 +419,20 @@ fn decompress_image(mut image_data: &[u8]) ->Option<()> {
         return None;
     }
 
+    // the zlib stream is the raw DEFLATE bitstream, read LSB-first, followed by a 4 byte
+    // big-endian Adler-32 of the decompressed data
+    if image_data.len() < 4 {
+        return None;
+    }
+    let trailer_start = image_data.len() - 4;
+    let stored_adler32 = u32::from_be_bytes(image_data[trailer_start..].try_into().ok()?);
+    let mut reader = BitReader::new(&image_data[..trailer_start]);
+    let mut output = Vec::new();
+
     let mut final_block = false;
     while !final_block {
-        let header = read_u8(&mut image_data)?;
-        let bfinal = header & 0x1;
-        let btype = (header >> 1) & 0x3;
-
-        println!("BTYPE: {} BFINAL: {}", btype, bfinal);
+        let bfinal = reader.read_bits(1)?;
+        let btype = reader.read_bits(2)?;
 
         if bfinal == 1 {
             final_block = true;
@@ -197,39 +440,384 @@ fn decompress_image(mut image_data: &[u8]) ->Option<()> {
 
         match btype {
             0b00 => {
-                let len = read_u16(&mut image_data)?;                
-                let _nlen = read_u16(&mut image_data)?;
-                println!("Uncompressed Chunk Length: {}", len);
-                read_bytes(&mut image_data, len as usize);
+                // stored blocks start on a byte boundary
+                reader.align_to_byte();
+                let len = reader.read_bits(16)? as u16;
+                let nlen = reader.read_bits(16)? as u16;
+
+                // NLEN is the one's complement of LEN; a mismatch means the stream is corrupt
+                if nlen != !len {
+                    return None;
+                }
+
+                if output.len().checked_add(len as usize)? > max_output_bytes {
+                    return None;
+                }
+                for _ in 0..len {
+                    output.push(reader.read_bits(8)? as u8);
+                }
             },
-            0b01 => {
-                break;
+            0b01 => inflate_block(&mut reader, &fixed_litlen_table(), &fixed_dist_table(), &mut output, max_output_bytes)?,
+            0b10 => {
+                let (lit_table, dist_table) = read_dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &lit_table, &dist_table, &mut output, max_output_bytes)?;
             },
-            0b10 => break,
             _ => return None
         }
     }
 
-    Some(())
+    if options.strict && adler32(&output) != stored_adler32 {
+        return None;
+    }
+
+    Some(output)
 }
 
-/// get a u32 from the file data and advance the pointer. Returns None if there isn't enough space
-/// left.
-fn read_u32(file: &mut &[u8]) -> Option<u32> {
-    let bytes = read_bytes(file, mem::size_of::<u32>())?;
-    let num = u32::from_be_bytes(match bytes.try_into() {
-        Ok(slice) => slice,
-        Err(_) => return None,
-    });
+/// compute the Adler-32 checksum used as the zlib stream trailer (RFC 1950 section 9)
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut s1 = 1u32;
+    let mut s2 = 0u32;
+    for &byte in data {
+        s1 = (s1 + byte as u32) % MOD_ADLER;
+        s2 = (s2 + s1) % MOD_ADLER;
+    }
+    (s2 << 16) | s1
+}
 
-    Some(num)
+/// A bit-level cursor over a byte slice. DEFLATE packs the bits of each byte starting from the
+/// least-significant bit, so bytes are pulled from the underlying slice into a buffer and bits
+/// are consumed from the low end of that buffer.
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, bit_buf: 0, bit_count: 0 }
+    }
+
+    /// Read up to 32 bits LSB-first, returned right-aligned in the result. Returns `None` if the
+    /// underlying data runs out first.
+    fn read_bits(&mut self, bits: u32) -> Option<u32> {
+        if bits > 32 {
+            return None;
+        }
+
+        while self.bit_count < bits {
+            let (&byte, rest) = self.data.split_first()?;
+            self.data = rest;
+            self.bit_buf |= (byte as u64) << self.bit_count;
+            self.bit_count += 8;
+        }
+
+        let mask = (1u64 << bits) - 1;
+        let value = (self.bit_buf & mask) as u32;
+        self.bit_buf >>= bits;
+        self.bit_count -= bits;
+
+        Some(value)
+    }
+
+    /// Discard the bits left over in the current partially-consumed byte so the next read starts
+    /// on a byte boundary, as required before a stored (BTYPE=00) block.
+    fn align_to_byte(&mut self) {
+        let drop = self.bit_count % 8;
+        self.bit_buf >>= drop;
+        self.bit_count -= drop;
+    }
+}
+
+/// A canonical Huffman decode table built from a list of per-symbol code lengths, as described in
+/// RFC 1951 section 3.2.2.
+struct HuffmanTable {
+    codes: HashMap<(u8, u16), u16>,
+    max_len: u8,
+}
+
+impl HuffmanTable {
+    fn from_lengths(lengths: &[u8]) -> HuffmanTable {
+        let max_len = lengths.iter().copied().max().unwrap_or(0);
+
+        let mut bl_count = vec![0u16; max_len as usize + 1];
+        for &len in lengths {
+            if len > 0 {
+                bl_count[len as usize] += 1;
+            }
+        }
+
+        let mut code = 0u16;
+        let mut next_code = vec![0u16; max_len as usize + 1];
+        for len in 1..=max_len as usize {
+            code = (code + bl_count[len - 1]) << 1;
+            next_code[len] = code;
+        }
+
+        let mut codes = HashMap::new();
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let assigned = next_code[len as usize];
+            next_code[len as usize] += 1;
+            codes.insert((len, assigned), symbol as u16);
+        }
+
+        HuffmanTable { codes, max_len }
+    }
+
+    /// Decode one symbol by reading a bit at a time, building up the code MSB-first, until the
+    /// accumulated (length, code) pair matches an assigned canonical code.
+    fn decode(&self, reader: &mut BitReader) -> Option<u16> {
+        let mut code = 0u16;
+        for len in 1..=self.max_len {
+            let bit = reader.read_bits(1)? as u16;
+            code = (code << 1) | bit;
+            if let Some(&symbol) = self.codes.get(&(len, code)) {
+                return Some(symbol);
+            }
+        }
+        None
+    }
+}
+
+/// the fixed literal/length code table used by BTYPE=01 blocks (RFC 1951 section 3.2.6)
+fn fixed_litlen_table() -> HuffmanTable {
+    let mut lengths = [0u8; 288];
+    for l in &mut lengths[0..144] { *l = 8; }
+    for l in &mut lengths[144..256] { *l = 9; }
+    for l in &mut lengths[256..280] { *l = 7; }
+    for l in &mut lengths[280..288] { *l = 8; }
+    HuffmanTable::from_lengths(&lengths)
+}
+
+/// the fixed distance code table used by BTYPE=01 blocks (RFC 1951 section 3.2.6)
+fn fixed_dist_table() -> HuffmanTable {
+    HuffmanTable::from_lengths(&[5u8; 32])
+}
+
+// base length/distance and extra bit counts per symbol (RFC 1951 section 3.2.5)
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// order in which the code-length alphabet's own code lengths are stored (RFC 1951 section 3.2.7)
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// read the HLIT/HDIST/HCLEN header of a BTYPE=10 block and build the literal/length and
+/// distance Huffman tables it describes
+fn read_dynamic_tables(reader: &mut BitReader) -> Option<(HuffmanTable, HuffmanTable)> {
+    let hlit = reader.read_bits(5)? as usize + 257;
+    let hdist = reader.read_bits(5)? as usize + 1;
+    let hclen = reader.read_bits(4)? as usize + 4;
+
+    let mut cl_lengths = [0u8; 19];
+    for i in 0..hclen {
+        cl_lengths[CODE_LENGTH_ORDER[i]] = reader.read_bits(3)? as u8;
+    }
+    let cl_table = HuffmanTable::from_lengths(&cl_lengths);
+
+    let mut lengths = Vec::with_capacity(hlit + hdist);
+    let mut prev = 0u8;
+    while lengths.len() < hlit + hdist {
+        match cl_table.decode(reader)? {
+            16 => {
+                let repeat = reader.read_bits(2)? + 3;
+                for _ in 0..repeat {
+                    lengths.push(prev);
+                }
+            }
+            17 => {
+                let repeat = reader.read_bits(3)? + 3;
+                lengths.extend(std::iter::repeat_n(0, repeat as usize));
+                prev = 0;
+            }
+            18 => {
+                let repeat = reader.read_bits(7)? + 11;
+                lengths.extend(std::iter::repeat_n(0, repeat as usize));
+                prev = 0;
+            }
+            sym => {
+                prev = sym as u8;
+                lengths.push(prev);
+            }
+        }
+    }
+    lengths.truncate(hlit + hdist);
+
+    let lit_table = HuffmanTable::from_lengths(&lengths[..hlit]);
+    let dist_table = HuffmanTable::from_lengths(&lengths[hlit..]);
+
+    Some((lit_table, dist_table))
+}
+
+/// decode one Huffman-compressed block (fixed or dynamic) into `output`, stopping at the
+/// end-of-block symbol (256)
+fn inflate_block(
+    reader: &mut BitReader,
+    lit_table: &HuffmanTable,
+    dist_table: &HuffmanTable,
+    output: &mut Vec<u8>,
+    max_output_bytes: usize,
+) -> Option<()> {
+    loop {
+        let symbol = lit_table.decode(reader)?;
+        match symbol {
+            0..=255 => {
+                if output.len() >= max_output_bytes {
+                    return None;
+                }
+                output.push(symbol as u8)
+            },
+            256 => return Some(()),
+            257..=285 => {
+                let idx = (symbol - 257) as usize;
+                let length = LENGTH_BASE[idx] as usize + reader.read_bits(LENGTH_EXTRA[idx])? as usize;
+
+                let dist_symbol = dist_table.decode(reader)? as usize;
+                let distance = DIST_BASE[dist_symbol] as usize + reader.read_bits(DIST_EXTRA[dist_symbol])? as usize;
+
+                // overlapping copies (distance < length) must proceed byte-by-byte
+                if distance > output.len() {
+                    return None;
+                }
+                if output.len().checked_add(length)? > max_output_bytes {
+                    return None;
+                }
+                let start = output.len() - distance;
+                for i in 0..length {
+                    let byte = output[start + i];
+                    output.push(byte);
+                }
+            },
+            _ => return None
+        }
+    }
+}
+
+/// the Paeth predictor used by filter type 4 (PNG spec section 9.4): pick whichever of `a`, `b`,
+/// `c` is closest to `a + b - c`, breaking ties in favor of `a` then `b`.
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i32 + b as i32 - c as i32;
+    let pa = (p - a as i32).abs();
+    let pb = (p - b as i32).abs();
+    let pc = (p - c as i32).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Adam7 interlacing splits the image into 7 passes, each a sub-sampled grid starting at
+// (x_start, y_start) and stepping by (x_step, y_step) (PNG spec section 8.2)
+const ADAM7_PASSES: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// the pixel width/height of a pass's sub-image, found by counting how many of the pass's
+/// sampled positions land inside the full image
+fn adam7_pass_dimensions(width: u32, height: u32, x_start: u32, y_start: u32, x_step: u32, y_step: u32) -> (u32, u32) {
+    let pass_width = width.saturating_sub(x_start).div_ceil(x_step);
+    let pass_height = height.saturating_sub(y_start).div_ceil(y_step);
+    (pass_width, pass_height)
+}
+
+/// unfilter an Adam7-interlaced image, inflated as 7 consecutive independent sub-images, and
+/// scatter each pass's reconstructed pixels back into the full-size output buffer
+fn unfilter_adam7(data: &[u8], width: u32, height: u32, bpp: usize) -> Option<Vec<u8>> {
+    let mut pixels = vec![0u8; width as usize * height as usize * bpp];
+    let mut pos = 0usize;
+
+    for &(x_start, y_start, x_step, y_step) in &ADAM7_PASSES {
+        let (pass_width, pass_height) = adam7_pass_dimensions(width, height, x_start, y_start, x_step, y_step);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+
+        let pass_stride = pass_width as usize * bpp;
+        let pass_len = pass_height as usize * (pass_stride + 1);
+        let pass_data = data.get(pos..pos + pass_len)?;
+        pos += pass_len;
+
+        let pass_pixels = unfilter(pass_data, pass_width, pass_height, bpp)?;
+        for row in 0..pass_height as usize {
+            for col in 0..pass_width as usize {
+                let src = row * pass_stride + col * bpp;
+                let dst_x = x_start as usize + col * x_step as usize;
+                let dst_y = y_start as usize + row * y_step as usize;
+                let dst = (dst_y * width as usize + dst_x) * bpp;
+                pixels[dst..dst + bpp].copy_from_slice(&pass_pixels[src..src + bpp]);
+            }
+        }
+    }
+
+    Some(pixels)
+}
+
+/// reverse PNG's per-scanline adaptive filtering, turning the inflated byte stream (one filter
+/// type byte followed by `width * bpp` bytes per row) into raw pixel bytes
+fn unfilter(data: &[u8], width: u32, height: u32, bpp: usize) -> Option<Vec<u8>> {
+    let stride = width as usize * bpp;
+    let mut pixels = vec![0u8; stride * height as usize];
+    let mut pos = 0usize;
+
+    for row in 0..height as usize {
+        let filter_type = *data.get(pos)?;
+        pos += 1;
+        let row_start = row * stride;
+
+        for col in 0..stride {
+            let a = if col >= bpp { pixels[row_start + col - bpp] } else { 0 };
+            let b = if row > 0 { pixels[row_start - stride + col] } else { 0 };
+            let c = if row > 0 && col >= bpp { pixels[row_start - stride + col - bpp] } else { 0 };
+            let x = *data.get(pos)?;
+            pos += 1;
+
+            let value = match filter_type {
+                0 => x,
+                1 => x.wrapping_add(a),
+                2 => x.wrapping_add(b),
+                3 => x.wrapping_add(((a as u16 + b as u16) / 2) as u8),
+                4 => x.wrapping_add(paeth_predictor(a, b, c)),
+                _ => return None,
+            };
+            pixels[row_start + col] = value;
+        }
+    }
+
+    Some(pixels)
 }
 
 /// get a u32 from the file data and advance the pointer. Returns None if there isn't enough space
 /// left.
-fn read_u16(file: &mut &[u8]) -> Option<u16> {
-    let bytes = read_bytes(file, mem::size_of::<u16>())?;
-    let num = u16::from_be_bytes(match bytes.try_into() {
+fn read_u32(file: &mut &[u8]) -> Option<u32> {
+    let bytes = read_bytes(file, mem::size_of::<u32>())?;
+    let num = u32::from_be_bytes(match bytes.try_into() {
         Ok(slice) => slice,
         Err(_) => return None,
     });
@@ -244,12 +832,9 @@ fn read_u8(file: &mut &[u8]) -> Option<u8> {
     Some(num_slice[0])
 }
 
-static mut BITS_READ: usize = 0;
-
 /// get an arbitrary amount of bytes from the file data and advance the pointer. Returns None if
 /// there isn't enough space left
 fn read_bytes<'a>(file: &mut &'a [u8], bytes: usize) -> Option<&'a [u8]> {
-    unsafe {BITS_READ = 0};
     if file.len() < bytes {
         return None;
     }
@@ -260,10 +845,281 @@ fn read_bytes<'a>(file: &mut &'a [u8], bytes: usize) -> Option<&'a [u8]> {
     Some(ret)
 }
 
-fn read_bits(file: &mut &[u8], bits: u32) -> Option<u32> {
-    if bits > 32 {
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the write-side counterpart to `BitReader`: packs bits LSB-first into bytes, matching the
+    /// order `BitReader::read_bits` consumes them in, so tests can hand-build DEFLATE streams
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bit_buf: u32,
+        bit_count: u32,
     }
 
-    Some(0)
+    impl BitWriter {
+        fn new() -> Self {
+            BitWriter { bytes: Vec::new(), bit_buf: 0, bit_count: 0 }
+        }
+
+        fn write_bits(&mut self, value: u32, bits: u32) {
+            let mask = if bits == 32 { u32::MAX } else { (1u32 << bits) - 1 };
+            self.bit_buf |= (value & mask) << self.bit_count;
+            self.bit_count += bits;
+            while self.bit_count >= 8 {
+                self.bytes.push((self.bit_buf & 0xFF) as u8);
+                self.bit_buf >>= 8;
+                self.bit_count -= 8;
+            }
+        }
+
+        /// write a symbol's canonical Huffman code, MSB-first, the way `HuffmanTable::decode`
+        /// reads it back
+        fn write_symbol(&mut self, table: &HuffmanTable, symbol: u16) {
+            let (len, code) = table
+                .codes
+                .iter()
+                .find_map(|(&(len, code), &sym)| (sym == symbol).then_some((len, code)))
+                .expect("symbol not present in table");
+            for i in (0..len).rev() {
+                self.write_bits(((code >> i) & 1) as u32, 1);
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            if self.bit_count > 0 {
+                self.bytes.push((self.bit_buf & 0xFF) as u8);
+            }
+            self.bytes
+        }
+    }
+
+    /// wrap a raw DEFLATE bitstream in a minimal zlib header and trailing Adler-32, as
+    /// `decompress_image` expects
+    fn zlib_wrap(deflate: &[u8], decompressed: &[u8]) -> Vec<u8> {
+        let mut stream = vec![0x78, 0x01];
+        stream.extend_from_slice(deflate);
+        stream.extend_from_slice(&adler32(decompressed).to_be_bytes());
+        stream
+    }
+
+    #[test]
+    fn fixed_huffman_block_round_trips() {
+        let lit_table = fixed_litlen_table();
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1); // BFINAL
+        writer.write_bits(0b01, 2); // BTYPE=01, fixed Huffman
+        for &byte in b"ABC" {
+            writer.write_symbol(&lit_table, byte as u16);
+        }
+        writer.write_symbol(&lit_table, 256); // end of block
+        let deflate = writer.finish();
+
+        let stream = zlib_wrap(&deflate, b"ABC");
+        let options = DecodeOptions::default();
+        assert_eq!(decompress_image(&stream, &options, 1024), Some(b"ABC".to_vec()));
+    }
+
+    #[test]
+    fn dynamic_huffman_block_round_trips() {
+        // a minimal dynamic block: a 257-entry lit/len alphabet where only 'A' (65) and the
+        // end-of-block symbol (256) have a code, and a single (unused) distance code
+        let mut lit_lengths = vec![0u8; 257];
+        lit_lengths[65] = 1;
+        lit_lengths[256] = 1;
+        let lit_table = HuffmanTable::from_lengths(&lit_lengths);
+
+        // the code-length alphabet used to transmit the lengths above: symbol 0 (explicit zero),
+        // symbol 1 (explicit one), and symbol 18 (repeat zero 11-138 times)
+        let mut cl_lengths = [0u8; 19];
+        cl_lengths[0] = 1;
+        cl_lengths[1] = 2;
+        cl_lengths[18] = 2;
+        let cl_table = HuffmanTable::from_lengths(&cl_lengths);
+
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1); // BFINAL
+        writer.write_bits(0b10, 2); // BTYPE=10, dynamic Huffman
+
+        writer.write_bits(0, 5); // HLIT = 257
+        writer.write_bits(0, 5); // HDIST = 1
+        writer.write_bits(18 - 4, 4); // HCLEN = 18, enough of CODE_LENGTH_ORDER to reach symbol 1
+
+        // the code-length-alphabet's own lengths, one 3 bit field per CODE_LENGTH_ORDER entry
+        for &symbol in &CODE_LENGTH_ORDER[0..18] {
+            writer.write_bits(cl_lengths[symbol] as u32, 3);
+        }
+
+        // describe the 257 lit/len lengths + 1 dist length (258 total) via the RLE alphabet:
+        // 65 zeros, a '1' (for literal 'A'), 190 more zeros, a '1' (for EOB), then one more zero
+        // for the lone (unused) distance code
+        writer.write_symbol(&cl_table, 18);
+        writer.write_bits(65 - 11, 7);
+        writer.write_symbol(&cl_table, 1);
+        writer.write_symbol(&cl_table, 18);
+        writer.write_bits(138 - 11, 7);
+        writer.write_symbol(&cl_table, 18);
+        writer.write_bits(52 - 11, 7);
+        writer.write_symbol(&cl_table, 1);
+        writer.write_symbol(&cl_table, 0);
+
+        writer.write_symbol(&lit_table, 65); // 'A'
+        writer.write_symbol(&lit_table, 256); // end of block
+        let deflate = writer.finish();
+
+        let stream = zlib_wrap(&deflate, b"A");
+        let options = DecodeOptions::default();
+        assert_eq!(decompress_image(&stream, &options, 1024), Some(b"A".to_vec()));
+    }
+
+    #[test]
+    fn stored_block_rejects_bad_nlen() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1); // BFINAL
+        writer.write_bits(0b00, 2); // BTYPE=00, stored
+        writer.write_bits(0, 5); // pad to the next byte boundary
+        writer.write_bits(3, 16); // LEN=3
+        writer.write_bits(3, 16); // NLEN should be !3, not 3 - corrupt
+        writer.write_bits(b'X' as u32, 8);
+        writer.write_bits(b'Y' as u32, 8);
+        writer.write_bits(b'Z' as u32, 8);
+        let deflate = writer.finish();
+
+        let stream = zlib_wrap(&deflate, b"XYZ");
+        let options = DecodeOptions::default();
+        assert_eq!(decompress_image(&stream, &options, 1024), None);
+    }
+
+    #[test]
+    fn scanline_filters_round_trip() {
+        let width = 2u32;
+        let height = 2u32;
+        let bpp = 1usize;
+        let stride = width as usize * bpp;
+        let raw = vec![10u8, 20, 30, 40];
+
+        for filter_type in 0u8..=4 {
+            let mut filtered = Vec::new();
+            for row in 0..height as usize {
+                filtered.push(filter_type);
+                let row_start = row * stride;
+                for col in 0..stride {
+                    let a = if col >= bpp { raw[row_start + col - bpp] } else { 0 };
+                    let b = if row > 0 { raw[row_start - stride + col] } else { 0 };
+                    let c = if row > 0 && col >= bpp { raw[row_start - stride + col - bpp] } else { 0 };
+                    let x = raw[row_start + col];
+                    let byte = match filter_type {
+                        0 => x,
+                        1 => x.wrapping_sub(a),
+                        2 => x.wrapping_sub(b),
+                        3 => x.wrapping_sub(((a as u16 + b as u16) / 2) as u8),
+                        4 => x.wrapping_sub(paeth_predictor(a, b, c)),
+                        _ => unreachable!(),
+                    };
+                    filtered.push(byte);
+                }
+            }
+
+            assert_eq!(unfilter(&filtered, width, height, bpp), Some(raw.clone()), "filter type {filter_type}");
+        }
+    }
+
+    #[test]
+    fn adam7_descatter_round_trips() {
+        let width = 8u32;
+        let height = 8u32;
+        let bpp = 1usize;
+        let raw: Vec<u8> = (0..width * height).map(|i| i as u8).collect();
+
+        let mut filtered = Vec::new();
+        for &(x_start, y_start, x_step, y_step) in &ADAM7_PASSES {
+            let (pass_width, pass_height) = adam7_pass_dimensions(width, height, x_start, y_start, x_step, y_step);
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+            for row in 0..pass_height {
+                filtered.push(0); // filter type None
+                for col in 0..pass_width {
+                    let src_x = x_start + col * x_step;
+                    let src_y = y_start + row * y_step;
+                    let idx = (src_y * width + src_x) as usize * bpp;
+                    filtered.extend_from_slice(&raw[idx..idx + bpp]);
+                }
+            }
+        }
+
+        assert_eq!(unfilter_adam7(&filtered, width, height, bpp), Some(raw));
+    }
+
+    #[test]
+    fn depalettize_expands_indices_and_applies_trns_alpha() {
+        let palette = [255u8, 0, 0, 0, 255, 0, 0, 0, 255]; // red, green, blue
+        let indices = [0u8, 1, 2, 0];
+
+        assert_eq!(
+            depalettize(&indices, &palette, None),
+            Some(vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 0, 0])
+        );
+
+        // tRNS covers only the first two palette entries; the third defaults to fully opaque
+        let trns = [10u8, 20];
+        assert_eq!(
+            depalettize(&indices, &palette, Some(&trns)),
+            Some(vec![255, 0, 0, 10, 0, 255, 0, 20, 0, 0, 255, 255, 255, 0, 0, 10])
+        );
+    }
+
+    #[test]
+    fn grayscale_trns_marks_matching_sample_transparent() {
+        let samples = [5u8, 10, 5, 200];
+        assert_eq!(apply_grayscale_trns(&samples, 5), vec![5, 0, 10, 255, 5, 0, 200, 255]);
+    }
+
+    #[test]
+    fn crc32_matches_known_test_vector() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn adler32_matches_known_test_vector() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn strict_mode_rejects_adler32_mismatch() {
+        let lit_table = fixed_litlen_table();
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1);
+        writer.write_bits(0b01, 2);
+        writer.write_symbol(&lit_table, b'h' as u16);
+        writer.write_symbol(&lit_table, 256);
+        let deflate = writer.finish();
+
+        let mut stream = zlib_wrap(&deflate, b"h");
+        let last = stream.len() - 1;
+        stream[last] ^= 0xFF; // corrupt the Adler-32 trailer
+
+        let strict = DecodeOptions { strict: true };
+        let lenient = DecodeOptions { strict: false };
+        assert_eq!(decompress_image(&stream, &strict, 1024), None);
+        assert_eq!(decompress_image(&stream, &lenient, 1024), Some(b"h".to_vec()));
+    }
+
+    #[test]
+    fn strict_mode_rejects_crc32_mismatch() {
+        let mut good = Vec::new();
+        good.extend_from_slice(&0u32.to_be_bytes()); // zero-length IEND chunk
+        good.extend_from_slice(b"IEND");
+        good.extend_from_slice(&crc32(b"IEND").to_be_bytes());
+
+        let strict = DecodeOptions { strict: true };
+        let lenient = DecodeOptions { strict: false };
+        assert!(read_chunks(&good, &strict).is_some());
+
+        let mut bad = good.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 0xFF;
+        assert!(read_chunks(&bad, &strict).is_none());
+        assert!(read_chunks(&bad, &lenient).is_some());
+    }
 }
\ No newline at end of file